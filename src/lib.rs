@@ -13,9 +13,18 @@ extern crate embedded_hal as hal;
 #[macro_use(block)]
 extern crate nb;
 
+use hal::blocking::delay::DelayUs;
 use hal::digital::v2::InputPin;
 use hal::digital::v2::OutputPin;
 
+/// PD_SCK must be held high at least this long (in microseconds) to put the
+/// chip into power-down mode.
+const POWER_DOWN_US: u16 = 60;
+
+/// Minimum time (in microseconds) PD_SCK must be held high or low during a
+/// clock pulse, so bit-banging stays correct regardless of MCU speed.
+const CLOCK_PULSE_US: u16 = 1;
+
 /// Maximum ADC value
 pub const MAX_VALUE: i32 = (1 << 23) - 1;
 
@@ -27,6 +36,8 @@ pub struct Hx711<IN, OUT> {
     dout: IN,
     pd_sck: OUT,
     mode: Mode,
+    offset: i32,
+    scale: f32,
 }
 
 impl<IN, OUT, PINERR> Hx711<IN, OUT>
@@ -40,28 +51,125 @@ where
             dout,
             pd_sck,
             mode: Mode::ChAGain128,
+            offset: 0,
+            scale: 1.0,
+        }
+    }
+
+    /// Tare the scale: average `samples` raw readings and store the result
+    /// as the zero offset, so that subsequent calls to [`read_weight`] report
+    /// the load relative to the current state of the scale.
+    ///
+    /// [`read_weight`]: struct.Hx711.html#method.read_weight
+    pub fn tare<D: DelayUs<u16>>(&mut self, delay: &mut D, samples: u16) -> Result<(), Error<PINERR>> {
+        self.offset = self.read_averaged(delay, samples)?;
+        Ok(())
+    }
+
+    /// Directly set the counts-per-unit scale factor used by [`read_weight`].
+    ///
+    /// [`read_weight`]: struct.Hx711.html#method.read_weight
+    pub fn set_scale(&mut self, scale: f32) {
+        self.scale = scale;
+    }
+
+    /// Perform a two-point calibration: given the raw reading obtained at a
+    /// known reference weight, derive the scale factor relative to the
+    /// current zero offset (see [`tare`]) so that
+    /// `scale = (raw_at_known - offset) / known_weight`.
+    ///
+    /// [`tare`]: struct.Hx711.html#method.tare
+    pub fn set_scale_from_known(&mut self, known_weight: f32, raw_at_known: i32) {
+        self.scale = (raw_at_known - self.offset) as f32 / known_weight;
+    }
+
+    /// Read the current weight, applying the stored zero offset and scale
+    /// factor: `(raw - offset) / scale`.
+    pub fn read_weight<D: DelayUs<u16>>(&mut self, delay: &mut D) -> Result<f32, Error<PINERR>> {
+        let raw = block!(self.retrieve(delay))?;
+        Ok((raw - self.offset) as f32 / self.scale)
+    }
+
+    /// Perform `samples` conversions and return their mean, reducing noise
+    /// in a single reading.
+    pub fn read_averaged<D: DelayUs<u16>>(
+        &mut self,
+        delay: &mut D,
+        samples: u16,
+    ) -> Result<i32, Error<PINERR>> {
+        if samples == 0 {
+            return Err(Error::ZeroSamples);
+        }
+        let mut total: i64 = 0;
+        for _ in 0..samples {
+            total += i64::from(block!(self.retrieve(delay))?);
+        }
+        Ok((total / i64::from(samples)) as i32)
+    }
+
+    /// Read a single conversion, polling readiness for at most `max_wait_us`
+    /// microseconds before giving up with [`Error::Timeout`].
+    ///
+    /// [`Error::Timeout`]: enum.Error.html#variant.Timeout
+    pub fn read_blocking_timeout<D: DelayUs<u16>>(
+        &mut self,
+        delay: &mut D,
+        max_wait_us: u32,
+    ) -> Result<i32, Error<PINERR>> {
+        let mut waited_us: u32 = 0;
+        loop {
+            match self.retrieve(delay) {
+                Ok(value) => return Ok(value),
+                Err(nb::Error::Other(e)) => return Err(Error::Pin(e)),
+                Err(nb::Error::WouldBlock) => {
+                    if waited_us >= max_wait_us {
+                        return Err(Error::Timeout);
+                    }
+                    delay.delay_us(CLOCK_PULSE_US);
+                    waited_us += u32::from(CLOCK_PULSE_US);
+                }
+            }
         }
     }
 
     /// Set the mode (channel and gain).
-    pub fn set_mode(&mut self, mode: Mode) -> Result<(), PINERR>{
+    pub fn set_mode<D: DelayUs<u16>>(&mut self, mode: Mode, delay: &mut D) -> Result<(), PINERR>{
         self.mode = mode;
-        block!(self.retrieve())?;
+        block!(self.retrieve(delay))?;
         Ok(())
     }
 
-    /// Reset the chip. Mode is Channel A Gain 128 after reset.
-    pub fn reset(&mut self) -> Result<(), PINERR> {
+    /// Power down the chip by holding PD_SCK high for at least 60us. No
+    /// conversions happen while powered down.
+    pub fn power_down<D: DelayUs<u16>>(&mut self, delay: &mut D) -> Result<(), PINERR> {
         self.pd_sck.set_high()?;
-        for _ in 1..3 {
-            self.dout.is_high()?;
-        }
-        self.pd_sck.set_low()?;
+        delay.delay_us(POWER_DOWN_US);
         Ok(())
     }
 
+    /// Power up the chip by driving PD_SCK low again, then restore the
+    /// configured [`Mode`], since power-up always resets it to Channel A,
+    /// Gain 128.
+    ///
+    /// [`Mode`]: enum.Mode.html
+    pub fn power_up<D: DelayUs<u16>>(&mut self, delay: &mut D) -> Result<(), PINERR> {
+        self.pd_sck.set_low()?;
+        // The chip itself is now back in Channel A, Gain 128; re-send the
+        // pulses for the configured mode to restore it.
+        self.set_mode(self.mode, delay)
+    }
+
+    /// Reset the chip by power-cycling it. The configured mode is restored
+    /// afterwards (see [`power_up`]).
+    ///
+    /// [`power_up`]: struct.Hx711.html#method.power_up
+    pub fn reset<D: DelayUs<u16>>(&mut self, delay: &mut D) -> Result<(), PINERR> {
+        self.power_down(delay)?;
+        self.power_up(delay)
+    }
+
     /// Retrieve the latest conversion value if available
-    pub fn retrieve(&mut self) -> nb::Result<i32, PINERR> {
+    pub fn retrieve<D: DelayUs<u16>>(&mut self, delay: &mut D) -> nb::Result<i32, PINERR> {
         self.pd_sck.set_low()?;
         if self.dout.is_high()? {
             // Conversion not ready yet
@@ -73,23 +181,44 @@ where
             // Read 24 bits
             count <<= 1;
             self.pd_sck.set_high()?;
+            delay.delay_us(CLOCK_PULSE_US);
             self.pd_sck.set_low()?;
+            delay.delay_us(CLOCK_PULSE_US);
             if self.dout.is_high()? {
                 count += 1;
             }
         }
 
         // Continue to set mode for next conversion
-        let n_reads = self.mode as u16;
+        let n_reads = self.mode.pulses();
         for _ in 0..n_reads {
             self.pd_sck.set_high()?;
+            delay.delay_us(CLOCK_PULSE_US);
             self.pd_sck.set_low()?;
+            delay.delay_us(CLOCK_PULSE_US);
         }
 
         Ok(i24_to_i32(count))
     }
 }
 
+/// Errors that can occur while communicating with the HX711.
+#[derive(Debug)]
+pub enum Error<PINERR> {
+    /// An error occurred toggling a GPIO pin.
+    Pin(PINERR),
+    /// Timed out waiting for a conversion to become ready.
+    Timeout,
+    /// Requested an average of zero samples, which has no well-defined mean.
+    ZeroSamples,
+}
+
+impl<PINERR> From<PINERR> for Error<PINERR> {
+    fn from(err: PINERR) -> Self {
+        Error::Pin(err)
+    }
+}
+
 /// The HX711 can run in three modes:
 #[derive(Copy, Clone)]
 pub enum Mode {
@@ -101,6 +230,43 @@ pub enum Mode {
     ChBGain64 = 3,
 }
 
+impl Mode {
+    /// All modes the HX711 supports, in the same order as their pulse count.
+    pub const ALL: [Mode; 3] = [Mode::ChAGain128, Mode::ChBGain32, Mode::ChBGain64];
+
+    /// Number of clock pulses sent after the 24 data bits to select this
+    /// mode for the next conversion.
+    pub fn pulses(self) -> u16 {
+        self as u16
+    }
+
+    /// The gain factor (128, 64 or 32) this mode applies to the conversion.
+    pub fn gain(self) -> u8 {
+        match self {
+            Mode::ChAGain128 => 128,
+            Mode::ChBGain32 => 32,
+            Mode::ChBGain64 => 64,
+        }
+    }
+
+    /// The input channel this mode reads from.
+    pub fn channel(self) -> Channel {
+        match self {
+            Mode::ChAGain128 => Channel::A,
+            Mode::ChBGain32 | Mode::ChBGain64 => Channel::B,
+        }
+    }
+}
+
+/// The two input channels the HX711 can read from.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Channel {
+    /// Channel A
+    A,
+    /// Channel B
+    B,
+}
+
 /// Convert 24 bit signed integer to i32
 fn i24_to_i32(x: i32) -> i32 {
     if x >= 0x800000 {
@@ -113,6 +279,239 @@ fn i24_to_i32(x: i32) -> i32 {
 #[cfg(test)]
 mod tests {
     use crate::*;
+    use core::cell::Cell;
+    use core::convert::Infallible;
+
+    /// An output pin that counts how many times it was driven high/low.
+    struct CountingPin {
+        highs: Cell<u32>,
+        lows: Cell<u32>,
+    }
+
+    impl CountingPin {
+        fn new() -> Self {
+            CountingPin {
+                highs: Cell::new(0),
+                lows: Cell::new(0),
+            }
+        }
+    }
+
+    impl OutputPin for CountingPin {
+        type Error = Infallible;
+        fn set_high(&mut self) -> Result<(), Infallible> {
+            self.highs.set(self.highs.get() + 1);
+            Ok(())
+        }
+        fn set_low(&mut self) -> Result<(), Infallible> {
+            self.lows.set(self.lows.get() + 1);
+            Ok(())
+        }
+    }
+
+    /// An input pin that replays a fixed sequence of bits, one per call.
+    struct ScriptedPin<'a> {
+        bits: &'a [bool],
+        next: Cell<usize>,
+    }
+
+    impl<'a> ScriptedPin<'a> {
+        fn new(bits: &'a [bool]) -> Self {
+            ScriptedPin {
+                bits,
+                next: Cell::new(0),
+            }
+        }
+    }
+
+    impl<'a> InputPin for ScriptedPin<'a> {
+        type Error = Infallible;
+        fn is_high(&self) -> Result<bool, Infallible> {
+            let i = self.next.get();
+            self.next.set(i + 1);
+            Ok(self.bits[i])
+        }
+        fn is_low(&self) -> Result<bool, Infallible> {
+            Ok(!self.is_high()?)
+        }
+    }
+
+    /// A delay that does nothing but records how many microseconds it was
+    /// asked to wait.
+    struct CountingDelay {
+        total_us: u32,
+    }
+
+    impl CountingDelay {
+        fn new() -> Self {
+            CountingDelay { total_us: 0 }
+        }
+    }
+
+    impl DelayUs<u16> for CountingDelay {
+        fn delay_us(&mut self, us: u16) {
+            self.total_us += u32::from(us);
+        }
+    }
+
+    /// A DOUT script for one conversion: not-ready is never signalled, then
+    /// `value`'s 24 data bits follow MSB first.
+    fn conversion_bits(value: i32) -> [bool; 25] {
+        let mut bits = [false; 25];
+        for i in 0..24 {
+            bits[1 + i] = (value >> (23 - i)) & 1 == 1;
+        }
+        bits
+    }
+
+    #[test]
+    fn tare_sets_offset_to_the_average_reading() {
+        let bits = conversion_bits(500);
+        let mut hx = Hx711::new(ScriptedPin::new(&bits), CountingPin::new());
+        let mut delay = CountingDelay::new();
+
+        hx.tare(&mut delay, 1).unwrap();
+
+        assert_eq!(hx.offset, 500);
+    }
+
+    #[test]
+    fn tare_rejects_zero_samples() {
+        let bits = conversion_bits(0);
+        let mut hx = Hx711::new(ScriptedPin::new(&bits), CountingPin::new());
+        let mut delay = CountingDelay::new();
+
+        match hx.tare(&mut delay, 0) {
+            Err(Error::ZeroSamples) => {}
+            other => panic!("expected Error::ZeroSamples, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn set_scale_from_known_derives_counts_per_unit() {
+        let bits = conversion_bits(0);
+        let mut hx = Hx711::new(ScriptedPin::new(&bits), CountingPin::new());
+        hx.offset = 100;
+
+        hx.set_scale_from_known(5.0, 600);
+
+        assert_eq!(hx.scale, 100.0);
+    }
+
+    #[test]
+    fn read_weight_applies_offset_and_scale() {
+        let bits = conversion_bits(700);
+        let mut hx = Hx711::new(ScriptedPin::new(&bits), CountingPin::new());
+        let mut delay = CountingDelay::new();
+        hx.offset = 200;
+        hx.set_scale(2.0);
+
+        let weight = hx.read_weight(&mut delay).unwrap();
+
+        assert_eq!(weight, 250.0);
+    }
+
+    #[test]
+    fn power_down_holds_pd_sck_high_for_60us() {
+        let bits = conversion_bits(0);
+        let mut hx = Hx711::new(ScriptedPin::new(&bits), CountingPin::new());
+        let mut delay = CountingDelay::new();
+
+        hx.power_down(&mut delay).unwrap();
+
+        assert_eq!(hx.pd_sck.highs.get(), 1);
+        assert_eq!(delay.total_us, u32::from(POWER_DOWN_US));
+    }
+
+    #[test]
+    fn power_up_restores_the_configured_mode() {
+        let bits = conversion_bits(0);
+        let mut hx = Hx711::new(ScriptedPin::new(&bits), CountingPin::new());
+        let mut delay = CountingDelay::new();
+        hx.mode = Mode::ChBGain32;
+
+        hx.power_up(&mut delay).unwrap();
+
+        // PD_SCK driven low once directly, plus once more at the start of
+        // the conversion that re-clocks the mode's pulses back in.
+        let pulses = u32::from(Mode::ChBGain32.pulses());
+        assert_eq!(hx.pd_sck.lows.get(), 2 + 24 + pulses);
+        assert_eq!(hx.pd_sck.highs.get(), 24 + pulses);
+    }
+
+    #[test]
+    fn reset_power_cycles_and_restores_mode() {
+        let bits = conversion_bits(0);
+        let mut hx = Hx711::new(ScriptedPin::new(&bits), CountingPin::new());
+        let mut delay = CountingDelay::new();
+        hx.mode = Mode::ChBGain64;
+
+        hx.reset(&mut delay).unwrap();
+
+        assert_eq!(hx.mode as u16, Mode::ChBGain64 as u16);
+        assert!(delay.total_us >= u32::from(POWER_DOWN_US));
+    }
+
+    #[test]
+    fn retrieve_waits_around_every_clock_pulse() {
+        let bits = conversion_bits(42);
+        let mut hx = Hx711::new(ScriptedPin::new(&bits), CountingPin::new());
+        let mut delay = CountingDelay::new();
+
+        let value = block!(hx.retrieve(&mut delay)).unwrap();
+
+        assert_eq!(value, 42);
+        // 24 data-bit pulses plus the trailing gain-select pulses, each
+        // waited on once high and once low.
+        let pulses = 24 + u32::from(Mode::ChAGain128.pulses());
+        assert_eq!(delay.total_us, pulses * 2 * u32::from(CLOCK_PULSE_US));
+    }
+
+    #[test]
+    fn read_averaged_returns_the_mean_of_n_conversions() {
+        let mut bits = [false; 50];
+        bits[0..25].copy_from_slice(&conversion_bits(10));
+        bits[25..50].copy_from_slice(&conversion_bits(20));
+        let mut hx = Hx711::new(ScriptedPin::new(&bits), CountingPin::new());
+        let mut delay = CountingDelay::new();
+
+        assert_eq!(hx.read_averaged(&mut delay, 2).unwrap(), 15);
+    }
+
+    #[test]
+    fn read_averaged_rejects_zero_samples() {
+        let bits = conversion_bits(0);
+        let mut hx = Hx711::new(ScriptedPin::new(&bits), CountingPin::new());
+        let mut delay = CountingDelay::new();
+
+        match hx.read_averaged(&mut delay, 0) {
+            Err(Error::ZeroSamples) => {}
+            other => panic!("expected Error::ZeroSamples, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn read_blocking_timeout_succeeds_once_ready() {
+        // DOUT stays busy for the first two polls, then signals a reading of 7.
+        let mut bits = [true; 27];
+        bits[2..].copy_from_slice(&conversion_bits(7));
+        let mut hx = Hx711::new(ScriptedPin::new(&bits), CountingPin::new());
+        let mut delay = CountingDelay::new();
+
+        assert_eq!(hx.read_blocking_timeout(&mut delay, 100).unwrap(), 7);
+    }
+
+    #[test]
+    fn read_blocking_timeout_times_out_when_dout_never_drops() {
+        let bits = [true; 10];
+        let mut hx = Hx711::new(ScriptedPin::new(&bits), CountingPin::new());
+        let mut delay = CountingDelay::new();
+
+        match hx.read_blocking_timeout(&mut delay, 5) {
+            Err(Error::Timeout) => {}
+            other => panic!("expected Error::Timeout, got {:?}", other),
+        }
+    }
 
     #[test]
     fn convert() {
@@ -121,4 +520,17 @@ mod tests {
         assert_eq!(i24_to_i32(0xFFFFFF), -1);
         assert_eq!(i24_to_i32(0xFFFFF3), -13);
     }
+
+    #[test]
+    fn mode_table() {
+        for mode in Mode::ALL.iter().copied() {
+            assert_eq!(mode.pulses(), mode as u16);
+        }
+        assert_eq!(Mode::ChAGain128.gain(), 128);
+        assert_eq!(Mode::ChBGain32.gain(), 32);
+        assert_eq!(Mode::ChBGain64.gain(), 64);
+        assert_eq!(Mode::ChAGain128.channel(), Channel::A);
+        assert_eq!(Mode::ChBGain32.channel(), Channel::B);
+        assert_eq!(Mode::ChBGain64.channel(), Channel::B);
+    }
 }